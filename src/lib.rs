@@ -1,9 +1,10 @@
 use fastembed::{
     RerankResult, TextEmbedding, TextRerank, TokenizerFiles, UserDefinedEmbeddingModel, UserDefinedRerankingModel,
 };
+use pgrx::iter::TableIterator;
 use pgrx::prelude::*;
 use std::cell::OnceCell;
-use text_splitter::{ChunkConfig, TextSplitter};
+use text_splitter::{ChunkConfig, MarkdownSplitter, TextSplitter};
 use tokenizers::{AddedToken, Tokenizer};
 
 use lopdf::{Bookmark, Document, Object, ObjectId};
@@ -36,10 +37,8 @@ macro_rules! local_model {
 
 // === OpenAI embeddings ===
 
-#[pg_extern(immutable, strict)]
-fn embedding_openai_raw(model: &str, input: &str, key: &str) -> pgrx::JsonB {
+fn openai_embeddings_request(json_body: serde_json::Value, key: &str) -> serde_json::Value {
     let auth = format!("Bearer {key}");
-    let json_body = ureq::json!({ "model": model, "input": input });
 
     let response = match ureq::post("https://api.openai.com/v1/embeddings")
         .set("Authorization", auth.as_str())
@@ -56,10 +55,36 @@ fn embedding_openai_raw(model: &str, input: &str, key: &str) -> pgrx::JsonB {
     };
     match response.into_json() {
         Err(err) => error!("{ERR_PREFIX} Failed to parse JSON received from OpenAI API: {err}"),
-        Ok(value) => pgrx::JsonB(value),
+        Ok(value) => value,
     }
 }
 
+#[pg_extern(immutable, strict)]
+fn embedding_openai_raw(model: &str, input: &str, key: &str) -> pgrx::JsonB {
+    let json_body = ureq::json!({ "model": model, "input": input });
+    pgrx::JsonB(openai_embeddings_request(json_body, key))
+}
+
+// NOTE. Named to overload `embedding_openai_raw` at the SQL level: Postgres dispatches on the
+// `text` vs `text[]` argument, so callers don't need a separate function name for the batch form.
+#[pg_extern(immutable, strict, name = "embedding_openai_raw")]
+fn embedding_openai_raw_batch(model: &str, inputs: Vec<&str>, key: &str) -> Vec<pgrx::JsonB> {
+    let json_body = ureq::json!({ "model": model, "input": inputs });
+    let value = openai_embeddings_request(json_body, key);
+    let data = match value.get("data").and_then(|data| data.as_array()) {
+        None => error!("{ERR_PREFIX} Unexpected response shape from OpenAI API: missing data array"),
+        Some(data) => data,
+    };
+    if data.len() != inputs.len() {
+        error!(
+            "{ERR_PREFIX} Unexpected response shape from OpenAI API: expected {} embeddings, got {}",
+            inputs.len(),
+            data.len()
+        );
+    }
+    data.iter().map(|entry| pgrx::JsonB(entry.clone())).collect()
+}
+
 // === Local embeddings ===
 
 // NOTE. It might be nice to expose this function directly, but as at 2024-07-08 pgrx
@@ -101,6 +126,204 @@ extension_sql!(
     name = "embedding_bge_small_en_v15_with_cast"
 );
 
+// Batch form: runs all inputs through a single `embed` call and SQL function call instead of one
+// per input, which matters when embedding the many chunks a single `chunks_by_tokens` document
+// produces. (The model itself is cached per backend, so it is loaded once either way.)
+#[pg_extern(immutable, strict)]
+fn _embedding_bge_small_en_v15_batch(
+    inputs: Vec<&str>,
+) -> TableIterator<'static, (name!(idx, i32), name!(embedding, Vec<f32>))> {
+    let vectors = embeddings_bge_small_en_v15(inputs);
+    TableIterator::new(vectors.into_iter().enumerate().map(|(idx, embedding)| (idx as i32, embedding)))
+}
+
+extension_sql!(
+    "CREATE FUNCTION embedding_bge_small_en_v15_batch(inputs text[]) RETURNS TABLE(idx int, embedding vector(384))
+    LANGUAGE SQL VOLATILE STRICT PARALLEL SAFE AS $$
+      SELECT idx, embedding::vector(384) FROM _embedding_bge_small_en_v15_batch(inputs);
+    $$;",
+    name = "embedding_bge_small_en_v15_batch_with_cast"
+);
+
+// === Document templating ===
+
+// Liquid resolves an undefined variable to nil and renders it as an empty string rather than
+// erroring, so a missing field would otherwise render silently instead of surfacing through
+// ERR_PREFIX. Collect every `{{ ... }}` output expression (path plus its filter names) and every
+// `{% for %}`/`{% assign %}`/`{% capture %}`-introduced local name by hand (the `liquid` crate
+// doesn't expose a public "strict variables" mode), then check each output path that isn't a
+// literal, a local name, or guarded by a `default` filter against the document before rendering.
+fn liquid_output_expressions(template: &str) -> Vec<(String, Vec<String>)> {
+    let mut expressions = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rest = &rest[start + 2..];
+        let Some(end) = rest.find("}}") else { break };
+        let mut parts = rest[..end].trim().split('|');
+        let path = parts.next().unwrap_or("").trim().to_string();
+        let filters: Vec<String> = parts
+            .map(|filter| filter.trim().split(':').next().unwrap_or("").trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if !path.is_empty() {
+            expressions.push((path, filters));
+        }
+        rest = &rest[end + 2..];
+    }
+    expressions
+}
+
+fn liquid_local_names(template: &str) -> std::collections::HashSet<String> {
+    let mut names = std::collections::HashSet::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{%") {
+        rest = &rest[start + 2..];
+        let Some(end) = rest.find("%}") else { break };
+        let mut words = rest[..end].trim().split_whitespace();
+        match words.next() {
+            Some("for") | Some("assign") | Some("capture") => {
+                if let Some(name) = words.next() {
+                    names.insert(name.to_string());
+                }
+            }
+            _ => {}
+        }
+        rest = &rest[end + 2..];
+    }
+    names
+}
+
+// A bare string/number/boolean/Liquid-literal output, e.g. `{{ "some text" }}`, isn't a document
+// reference at all.
+fn is_liquid_literal(path: &str) -> bool {
+    path.starts_with('\'')
+        || path.starts_with('"')
+        || path.parse::<f64>().is_ok()
+        || matches!(path, "true" | "false" | "nil" | "null" | "empty" | "blank")
+}
+
+fn check_template_fields(template: &str, document: &serde_json::Value) {
+    let locals = liquid_local_names(template);
+    for (path, filters) in liquid_output_expressions(template) {
+        if is_liquid_literal(&path) {
+            continue;
+        }
+        // A `default:` filter makes a missing field a non-issue, since supplying a fallback is
+        // the whole point of using it. No other filter is treated this way.
+        if filters.iter().any(|filter| filter == "default") {
+            continue;
+        }
+
+        let mut segments = path.split('.');
+        let Some(root) = segments.next() else { continue };
+        if locals.contains(root) || root == "forloop" {
+            continue;
+        }
+        let mut value = document;
+        for segment in std::iter::once(root).chain(segments) {
+            match value.get(segment) {
+                Some(next) => value = next,
+                None => error!("{ERR_PREFIX} Template references '{path}', which is not present in document"),
+            }
+        }
+    }
+}
+
+#[pg_extern(immutable, strict)]
+fn render_embedding_input(document: pgrx::JsonB, template: &str) -> String {
+    check_template_fields(template, &document.0);
+
+    let parser = match liquid::ParserBuilder::with_stdlib().build() {
+        Err(err) => error!("{ERR_PREFIX} Error building Liquid parser: {err}"),
+        Ok(parser) => parser,
+    };
+    let parsed_template = match parser.parse(template) {
+        Err(err) => error!("{ERR_PREFIX} Error parsing Liquid template: {err}"),
+        Ok(template) => template,
+    };
+    let globals: liquid::Object = match liquid::model::to_object(&document.0) {
+        Err(err) => error!("{ERR_PREFIX} Error converting document to a Liquid object: {err}"),
+        Ok(globals) => globals,
+    };
+    match parsed_template.render(&globals) {
+        Err(err) => error!("{ERR_PREFIX} Error rendering Liquid template: {err}"),
+        Ok(rendered) => rendered,
+    }
+}
+
+// === Embedder registry ===
+
+fn parse_embedding_options(options: &serde_json::Value) -> (Option<u32>, Option<String>) {
+    let dimensions = options.get("dimensions").and_then(|value| value.as_u64()).map(|value| value as u32);
+    let api_key = options.get("api_key").and_then(|value| value.as_str()).map(|value| value.to_string());
+    (dimensions, api_key)
+}
+
+fn parse_openai_embedding(value: &serde_json::Value) -> Vec<f32> {
+    let embedding = value
+        .get("data")
+        .and_then(|data| data.get(0))
+        .and_then(|entry| entry.get("embedding"))
+        .and_then(|embedding| embedding.as_array());
+    match embedding {
+        None => error!("{ERR_PREFIX} Unexpected response shape from OpenAI API: missing embedding"),
+        Some(embedding) => embedding.iter().filter_map(|value| value.as_f64()).map(|value| value as f32).collect(),
+    }
+}
+
+fn normalize_l2(vector: &mut [f32]) {
+    let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for value in vector.iter_mut() {
+            *value /= norm;
+        }
+    }
+}
+
+// NOTE. `options` carries per-source extras (`dimensions` for OpenAI's text-embedding-3 family,
+// `api_key` since OpenAI has no ambient credentials in this crate). Dispatches to whichever
+// source/model the caller names instead of growing one hardcoded function per model.
+#[pg_extern(immutable, strict, name = "_embedding")]
+fn embedding_dispatch(source: &str, model: &str, input: &str, options: pgrx::JsonB) -> Vec<f32> {
+    let (dimensions, api_key) = parse_embedding_options(&options.0);
+
+    match source {
+        "openai" => {
+            let key = match &api_key {
+                None => error!("{ERR_PREFIX} options.api_key is required for source 'openai'"),
+                Some(key) => key.as_str(),
+            };
+            let mut json_body = ureq::json!({ "model": model, "input": input });
+            if let Some(dimensions) = dimensions {
+                json_body["dimensions"] = dimensions.into();
+            }
+            let value = openai_embeddings_request(json_body, key);
+            let mut embedding = parse_openai_embedding(&value);
+            // text-embedding-3's native `dimensions` param already returns the right length, but
+            // truncating and renormalizing here keeps us correct even against older models/mirrors
+            // that ignore it and return the full-length vector.
+            if let Some(dimensions) = dimensions {
+                embedding.truncate(dimensions as usize);
+                normalize_l2(&mut embedding);
+            }
+            embedding
+        }
+        "local" => match model {
+            "bge_small_en_v15" => _embedding_bge_small_en_v15(input),
+            other => error!("{ERR_PREFIX} Unknown local model '{other}'"),
+        },
+        other => error!("{ERR_PREFIX} Unknown embedding source '{other}', expected 'openai' or 'local'"),
+    }
+}
+
+extension_sql!(
+    "CREATE FUNCTION embedding(source text, model text, input text, options jsonb) RETURNS vector
+    LANGUAGE SQL VOLATILE STRICT PARALLEL SAFE AS $$
+      SELECT _embedding(source, model, input, options)::vector;
+    $$;",
+    name = "embedding_with_cast"
+);
+
 // === Local reranking ===
 
 fn reranks_jina_v1_tiny_en_base(query: &str, documents: Vec<&str>) -> Vec<RerankResult> {
@@ -144,6 +367,67 @@ fn rerank_score_jina_v1_tiny_en(query: &str, document: &str) -> f32 {
     }
 }
 
+// Runs the model once and returns every facet (input index, rank, score) instead of making
+// callers pick one of the three functions above and invoke the model redundantly for the others.
+#[pg_extern(immutable, strict)]
+fn rerank_jina_v1_tiny_en(
+    query: &str,
+    documents: Vec<&str>,
+) -> TableIterator<'static, (name!(input_index, i32), name!(rank, i32), name!(score, f32))> {
+    let reranking = reranks_jina_v1_tiny_en_base(query, documents); // already sorted by score descending
+    TableIterator::new(
+        reranking.into_iter().enumerate().map(|(rank, rr)| (rr.index as i32, (rank + 1) as i32, rr.score as f32)),
+    )
+}
+
+// === Hybrid search ===
+
+/// Assigns each id a 1-based rank by sorting its list by score descending.
+fn ranks_by_score(ids: &[i32], scores: &[f32]) -> Vec<(i32, i64)> {
+    let mut indices: Vec<usize> = (0..ids.len()).collect();
+    indices.sort_by(|&i, &j| scores[j].partial_cmp(&scores[i]).unwrap());
+    indices.into_iter().enumerate().map(|(rank, i)| (ids[i], (rank + 1) as i64)).collect()
+}
+
+#[pg_extern(immutable, strict)]
+fn hybrid_rank(
+    keyword_ids: Vec<i32>,
+    keyword_scores: Vec<f32>,
+    semantic_ids: Vec<i32>,
+    semantic_scores: Vec<f32>,
+    k: default!(i32, 60),
+) -> Vec<i32> {
+    if keyword_ids.len() != keyword_scores.len() {
+        error!("{ERR_PREFIX} keyword_ids and keyword_scores must have the same length");
+    }
+    if semantic_ids.len() != semantic_scores.len() {
+        error!("{ERR_PREFIX} semantic_ids and semantic_scores must have the same length");
+    }
+    if k < 1 {
+        error!("{ERR_PREFIX} k must be >= 1");
+    }
+    if keyword_scores.iter().any(|score| score.is_nan()) || semantic_scores.iter().any(|score| score.is_nan()) {
+        error!("{ERR_PREFIX} keyword_scores and semantic_scores must not contain NaN");
+    }
+
+    // Reciprocal Rank Fusion: sum 1 / (k + rank) across whichever lists an id appears in,
+    // tracking the summed rank too so ties can be broken by it.
+    let mut fused: BTreeMap<i32, (f32, i64)> = BTreeMap::new();
+    for (id, rank) in ranks_by_score(&keyword_ids, &keyword_scores)
+        .into_iter()
+        .chain(ranks_by_score(&semantic_ids, &semantic_scores))
+    {
+        let entry = fused.entry(id).or_insert((0.0, 0));
+        entry.0 += 1.0 / (k as f32 + rank as f32);
+        entry.1 += rank;
+    }
+
+    let mut fused: Vec<(i32, f32, i64)> =
+        fused.into_iter().map(|(id, (score, rank_sum))| (id, score, rank_sum)).collect();
+    fused.sort_by(|(_, score1, rank1), (_, score2, rank2)| score2.partial_cmp(score1).unwrap().then(rank1.cmp(rank2)));
+    fused.into_iter().map(|(id, _, _)| id).collect()
+}
+
 // === Local splitting/chunking ===
 
 #[pg_extern(immutable, strict)]
@@ -160,65 +444,98 @@ fn chunks_by_characters(document: &str, max_characters: i32, max_overlap: i32) -
     chunks
 }
 
-#[pg_extern(immutable, strict)]
-fn chunks_by_tokens(document: &str, max_tokens: i32, max_overlap: i32) -> Vec<&str> {
+fn load_bge_small_en_v15_tokenizer() -> (Tokenizer, i32) {
+    let mut tokenizer = match Tokenizer::from_bytes(include_bytes!("../bge_small_en_v15/tokenizer.json")) {
+        Err(err) => error!("{ERR_PREFIX} Error loading tokenizer: {err}"),
+        Ok(tokenizer) => tokenizer,
+    };
+    let special_tokens_map: serde_json::Value =
+        match serde_json::from_slice(include_bytes!("../bge_small_en_v15/special_tokens_map.json")) {
+            Err(err) => error!("{ERR_PREFIX} Error loading special tokens: {err}"),
+            Ok(map) => map,
+        };
+    if let serde_json::Value::Object(root_object) = special_tokens_map {
+        for (_, value) in root_object.iter() {
+            if value.is_string() {
+                tokenizer.add_special_tokens(&[AddedToken {
+                    content: value.as_str().unwrap().into(),
+                    special: true,
+                    ..Default::default()
+                }]);
+            } else if value.is_object() {
+                tokenizer.add_special_tokens(&[AddedToken {
+                    content: value["content"].as_str().unwrap().into(),
+                    special: true,
+                    single_word: value["single_word"].as_bool().unwrap(),
+                    lstrip: value["lstrip"].as_bool().unwrap(),
+                    rstrip: value["rstrip"].as_bool().unwrap(),
+                    normalized: value["normalized"].as_bool().unwrap(),
+                }]);
+            }
+        }
+    }
+    let tokenizer_config: serde_json::Value =
+        match serde_json::from_slice(include_bytes!("../bge_small_en_v15/tokenizer_config.json")) {
+            Err(err) => error!("{ERR_PREFIX} Error loading tokenizer config: {err}"),
+            Ok(config) => config,
+        };
+    let model_max_length = match tokenizer_config["model_max_length"].as_f64() {
+        None => error!("{ERR_PREFIX} Invalid max model length in tokenizer config"),
+        Some(len) => len,
+    };
+
+    (tokenizer, model_max_length as i32)
+}
+
+// Shared by `chunks_by_tokens` and `chunks_by_markdown` so both size against the same bge
+// tokenizer instance instead of loading it twice.
+fn with_bge_small_en_v15_tokenizer<R>(f: impl FnOnce(&Tokenizer, i32) -> R) -> R {
     thread_local! {
         static CELL: OnceCell<(Tokenizer, i32)> = const { OnceCell::new() };
     }
     CELL.with(|cell| {
-        let (tokenizer, model_max_length) = cell.get_or_init(|| {
-            let mut tokenizer = match Tokenizer::from_bytes(include_bytes!("../bge_small_en_v15/tokenizer.json")) {
-                Err(err) => error!("{ERR_PREFIX} Error loading tokenizer: {err}"),
-                Ok(tokenizer) => tokenizer,
-            };
-            let special_tokens_map: serde_json::Value =
-                match serde_json::from_slice(include_bytes!("../bge_small_en_v15/special_tokens_map.json")) {
-                    Err(err) => error!("{ERR_PREFIX} Error loading special tokens: {err}"),
-                    Ok(map) => map,
-                };
-            if let serde_json::Value::Object(root_object) = special_tokens_map {
-                for (_, value) in root_object.iter() {
-                    if value.is_string() {
-                        tokenizer.add_special_tokens(&[AddedToken {
-                            content: value.as_str().unwrap().into(),
-                            special: true,
-                            ..Default::default()
-                        }]);
-                    } else if value.is_object() {
-                        tokenizer.add_special_tokens(&[AddedToken {
-                            content: value["content"].as_str().unwrap().into(),
-                            special: true,
-                            single_word: value["single_word"].as_bool().unwrap(),
-                            lstrip: value["lstrip"].as_bool().unwrap(),
-                            rstrip: value["rstrip"].as_bool().unwrap(),
-                            normalized: value["normalized"].as_bool().unwrap(),
-                        }]);
-                    }
-                }
-            }
-            let tokenizer_config: serde_json::Value = match serde_json::from_slice(include_bytes!("../bge_small_en_v15/tokenizer_config.json")) {
-                Err(err) => error!("{ERR_PREFIX} Error loading tokenizer config: {err}"),
-                Ok(config) => config,
-            };
-            let model_max_length = match tokenizer_config["model_max_length"].as_f64() {
-                None => error!("{ERR_PREFIX} Invalid max model length in tokenizer config"),
-                Some(len) => len,
-            };
+        let (tokenizer, model_max_length) = cell.get_or_init(load_bge_small_en_v15_tokenizer);
+        f(tokenizer, *model_max_length)
+    })
+}
 
-            (tokenizer, model_max_length as i32)
-        });
+fn check_token_chunk_bounds(max_tokens: i32, max_overlap: i32, model_max_length: i32) {
+    if !(max_tokens > 0 && max_tokens <= model_max_length && max_overlap >= 0 && max_overlap < model_max_length) {
+        error!(
+            "{ERR_PREFIX} max_tokens must be between 1 and {model_max_length}, and max_overlap must be between 0 and {}",
+            model_max_length - 1
+        );
+    }
+}
 
-        if !(max_tokens > 0 && max_tokens <= *model_max_length && max_overlap >= 0 && max_overlap < *model_max_length) {
-            error!("{ERR_PREFIX} max_tokens must be between 1 and {model_max_length}, and max_overlap must be between 0 and {}", model_max_length - 1);
-        }
+#[pg_extern(immutable, strict)]
+fn chunks_by_tokens(document: &str, max_tokens: i32, max_overlap: i32) -> Vec<&str> {
+    with_bge_small_en_v15_tokenizer(|tokenizer, model_max_length| {
+        check_token_chunk_bounds(max_tokens, max_overlap, model_max_length);
 
         let size_config = match ChunkConfig::new(max_tokens as usize).with_overlap(max_overlap as usize) {
             Err(err) => error!("{ERR_PREFIX} Error creating chunk config: {err}"),
             Ok(config) => config,
         };
         let splitter = TextSplitter::new(size_config.with_sizer(tokenizer));
-        let chunks = splitter.chunks(document).collect();
-        chunks
+        splitter.chunks(document).collect()
+    })
+}
+
+// Prefers breaking at Markdown structure (section headings, then paragraphs, then sentences)
+// before falling back to the token limit, so chunks don't straddle headings/code fences/list
+// items the way a flat `chunks_by_tokens` window can.
+#[pg_extern(immutable, strict)]
+fn chunks_by_markdown(document: &str, max_tokens: i32, max_overlap: i32) -> Vec<&str> {
+    with_bge_small_en_v15_tokenizer(|tokenizer, model_max_length| {
+        check_token_chunk_bounds(max_tokens, max_overlap, model_max_length);
+
+        let size_config = match ChunkConfig::new(max_tokens as usize).with_overlap(max_overlap as usize) {
+            Err(err) => error!("{ERR_PREFIX} Error creating chunk config: {err}"),
+            Ok(config) => config,
+        };
+        let splitter = MarkdownSplitter::new(size_config.with_sizer(tokenizer));
+        splitter.chunks(document).collect()
     })
 }
 
@@ -459,6 +776,58 @@ mod tests {
         assert!(crate::_embedding_bge_small_en_v15("hello world!") != crate::_embedding_bge_small_en_v15("bye moon!"));
     }
 
+    #[pg_test]
+    fn test_embedding_bge_small_en_v15_batch_matches_single() {
+        let batch: Vec<(i32, Vec<f32>)> = crate::_embedding_bge_small_en_v15_batch(vec!["hello world!", "bye moon!"]).collect();
+        assert!(batch.len() == 2);
+        assert!(batch[0] == (0, crate::_embedding_bge_small_en_v15("hello world!")));
+        assert!(batch[1] == (1, crate::_embedding_bge_small_en_v15("bye moon!")));
+    }
+
+    #[pg_test]
+    fn test_render_embedding_input() {
+        let document = pgrx::JsonB(serde_json::json!({
+            "title": "Hello world",
+            "meta": { "author": "Ada" },
+        }));
+        let rendered = crate::render_embedding_input(document, "{{ title }} by {{ meta.author }}");
+        assert!(rendered == "Hello world by Ada");
+    }
+
+    #[pg_test(error = "[NEON_AI] Template references 'meta.author', which is not present in document")]
+    fn test_render_embedding_input_missing_field() {
+        let document = pgrx::JsonB(serde_json::json!({ "title": "Hello world" }));
+        crate::render_embedding_input(document, "{{ title }} by {{ meta.author }}");
+    }
+
+    #[pg_test]
+    fn test_render_embedding_input_missing_field_with_default() {
+        let document = pgrx::JsonB(serde_json::json!({ "title": "Hello world" }));
+        let rendered =
+            crate::render_embedding_input(document, "{{ title }} by {{ meta.author | default: \"Unknown\" }}");
+        assert!(rendered == "Hello world by Unknown");
+    }
+
+    #[pg_test]
+    fn test_render_embedding_input_for_loop() {
+        let document = pgrx::JsonB(serde_json::json!({ "tags": ["a", "b", "c"] }));
+        let rendered = crate::render_embedding_input(document, "{% for tag in tags %}{{ tag }},{% endfor %}");
+        assert!(rendered == "a,b,c,");
+    }
+
+    #[pg_test]
+    fn test_embedding_dispatch_local() {
+        let options = pgrx::JsonB(serde_json::json!({}));
+        let embedding = crate::embedding_dispatch("local", "bge_small_en_v15", "hello world!", options);
+        assert!(embedding == crate::_embedding_bge_small_en_v15("hello world!"));
+    }
+
+    #[pg_test(error = "[NEON_AI] Unknown embedding source 'unknown', expected 'openai' or 'local'")]
+    fn test_embedding_dispatch_unknown_source() {
+        let options = pgrx::JsonB(serde_json::json!({}));
+        crate::embedding_dispatch("unknown", "bge_small_en_v15", "hello world!", options);
+    }
+
     #[pg_test]
     fn test_rerank_jina_v1_tiny_en() {
         let candidate_pets = vec!["crocodile", "hamster", "indeterminate", "floorboard", "cat"];
@@ -473,6 +842,46 @@ mod tests {
         assert!(sorted_pets == vec!["cat", "hamster", "crocodile", "floorboard", "indeterminate"]);
     }
 
+    #[pg_test]
+    fn test_rerank_jina_v1_tiny_en_table() {
+        let candidate_pets = vec!["crocodile", "hamster", "indeterminate", "floorboard", "cat"];
+        let rows: Vec<(i32, i32, f32)> = crate::rerank_jina_v1_tiny_en("pet", candidate_pets.clone()).collect();
+        assert!(rows.len() == candidate_pets.len());
+
+        // rows come back sorted by rank ascending, and rank should track score descending
+        let mut by_rank = rows.clone();
+        by_rank.sort_by_key(|(_, rank, _)| *rank);
+        assert!(rows == by_rank);
+        for pair in rows.windows(2) {
+            assert!(pair[0].2 >= pair[1].2);
+        }
+
+        let cat_row = rows.iter().find(|(input_index, _, _)| *input_index == 4).unwrap();
+        assert!(cat_row.1 == 1);
+    }
+
+    #[pg_test]
+    fn test_hybrid_rank() {
+        // id 1 ranks first in both lists, id 2 only appears in the keyword list,
+        // id 3 only appears in the semantic list.
+        let fused = crate::hybrid_rank(vec![1, 2], vec![0.9, 0.5], vec![1, 3], vec![0.8, 0.4], 60);
+        assert!(fused == vec![1, 2, 3]);
+    }
+
+    #[pg_test(error = "[NEON_AI] keyword_scores and semantic_scores must not contain NaN")]
+    fn test_hybrid_rank_rejects_nan_score() {
+        crate::hybrid_rank(vec![1], vec![f32::NAN], vec![1], vec![0.5], 60);
+    }
+
+    #[pg_test]
+    fn test_hybrid_rank_k_is_optional() {
+        let fused = Spi::get_one::<Vec<i32>>(
+            "SELECT hybrid_rank(ARRAY[1, 2], ARRAY[0.9, 0.5]::float4[], ARRAY[1, 3], ARRAY[0.8, 0.4]::float4[])",
+        )
+        .unwrap();
+        assert!(fused == Some(vec![1, 2, 3]));
+    }
+
     #[pg_test]
     fn test_chunk_by_characters() {
         assert!(
@@ -504,6 +913,12 @@ mod tests {
             ]
         );
     }
+
+    #[pg_test]
+    fn test_chunk_by_markdown_respects_headings() {
+        let chunks = crate::chunks_by_markdown("# Title\n\nFirst paragraph.\n\n# Next\n\nSecond paragraph.", 8, 0);
+        assert!(chunks.iter().all(|chunk| !(chunk.contains("Title") && chunk.contains("Next"))));
+    }
 }
 
 /// This module is required by `cargo pgrx test` invocations.